@@ -28,31 +28,138 @@ and then proceed with further commands.
 This model makes it super easy to accept
 nested transaction blocks and allow
 for simple rollbacks and commits too.
+
+Durability is handled by a pluggable Storage
+trait. A committed transaction (or a top-level
+SET/UNSET run outside of a transaction) is
+persisted through this trait, and main() reloads
+the last durable state from it before reading
+any commands from stdin. The only implementation
+today is FileStorage, which keeps an on-disk log
+of SET/UNSET lines and replays it on load.
 **************************************/
 
 #![allow(non_camel_case_types)]
 
 use std::io;
 use std::io::prelude::*;
+use std::fs::OpenOptions;
 use std::process;
 use std::string::*;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::fmt;
 use db_command::*;
 
+/* path of the on-disk log used by FileStorage */
+const DB_LOG_PATH: &'static str = "simple_db.log";
+
 enum db_command {
 	SET(String, i32),
 	GET(String),
 	UNSET(String),
 	NUMEQUALTO(i32),
+	RANGE(i32, i32),
+	KEYSWITHVALUE(i32),
 	END,
 	BEGIN,
 	ROLLBACK,
-	COMMIT
+	ROLLBACKTO(String),
+	COMMIT,
+	SAVEPOINT(String),
+	RELEASE(String)
 }
 
 struct transaction {
     key_val: HashMap<String, i32>,    // holds key value pairs within transaction
-    val_quant: HashMap<i32, i32>    // holds quantity of a given value
+    val_quant: HashMap<i32, i32>,    // holds quantity of a given value
+    value_index: BTreeMap<i32, HashSet<String>>    // ordered value -> keys index, backs RANGE/KEYSWITHVALUE
+}
+
+/* a single entry on the transaction stack. BEGIN pushes an anonymous
+   savepoint (name: None); SAVEPOINT pushes a named one so ROLLBACK TO
+   and RELEASE can target it directly instead of only the most recent entry.
+
+   Rather than cloning the whole database, each frame starts out empty and
+   records only the pre-image of whatever SET/UNSET actually touches while
+   this frame is on top: the previous value for each modified key, and the
+   previous count for each val_quant bucket disturbed by those changes. The
+   first touch of a key (or bucket) within a frame is the only one recorded,
+   since that's the value needed to undo everything that happened in this
+   frame. */
+struct savepoint {
+	name: Option<String>,
+	key_undo: HashMap<String, Option<i32>>,
+	val_undo: HashMap<i32, Option<i32>>
+}
+
+impl savepoint {
+	fn new(name: Option<String>) -> savepoint {
+		savepoint {
+			name: name,
+			key_undo: HashMap::new(),
+			val_undo: HashMap::new()
+		}
+	}
+}
+
+/* restores ct to the state it was in before sp's frame made any changes */
+fn apply_undo(ct: &mut transaction, sp: &savepoint) {
+	for (key, preimage) in sp.key_undo.iter() {
+		/* value_index is derived from key_val, so move the key between
+		   buckets as its key_val entry is restored */
+		if let Some(current_val) = ct.key_val.get(key).cloned() {
+			ct.unindex(key.as_str(), current_val);
+		}
+
+		match *preimage {
+			Some(value) => {
+				ct.key_val.insert(key.clone(), value);
+				ct.value_index.entry(value).or_insert_with(HashSet::new).insert(key.clone());
+			},
+			None => { ct.key_val.remove(key); }
+		}
+	}
+	for (value, preimage) in sp.val_undo.iter() {
+		match *preimage {
+			Some(count) => { ct.val_quant.insert(*value, count); },
+			None => { ct.val_quant.remove(value); }
+		}
+	}
+}
+
+/* folds a committed/released frame's undo log into its parent so the parent
+   can still undo back past it; first-touch pre-images win since they're the
+   oldest known state for that key/bucket */
+fn merge_undo(parent: &mut savepoint, child: savepoint) {
+	for (key, preimage) in child.key_undo {
+		parent.key_undo.entry(key).or_insert(preimage);
+	}
+	for (value, preimage) in child.val_undo {
+		parent.val_undo.entry(value).or_insert(preimage);
+	}
+}
+
+/* error type returned by mutating transaction operations */
+#[derive(Debug)]
+enum DbError {
+	KeyNotFound(String),
+	SavepointNotFound(String)
+}
+
+impl fmt::Display for DbError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			DbError::KeyNotFound(ref key) => write!(f, "key not found: {}", key),
+			DbError::SavepointNotFound(ref name) => write!(f, "no such savepoint: {}", name)
+		}
+	}
+}
+
+/* finds the most recently pushed stack entry tagged with the given savepoint name */
+fn find_savepoint(ts: &Vec<savepoint>, name: &str) -> Option<usize> {
+	ts.iter().rposition(|sp| sp.name.as_ref().map(|n| n.as_str()) == Some(name))
 }
 
 impl transaction {
@@ -60,12 +167,47 @@ impl transaction {
     fn new() -> transaction{
         transaction{
             key_val: HashMap::new(),
-            val_quant: HashMap::new()
+            val_quant: HashMap::new(),
+            value_index: BTreeMap::new()
         }
     }
 
+	/* removes key from the value_index bucket for value, dropping the bucket once it's empty */
+	fn unindex(&mut self, key: &str, value: i32) {
+		let bucket_now_empty = match self.value_index.get_mut(&value) {
+			Some(bucket) => {
+				bucket.remove(key);
+				bucket.is_empty()
+			},
+			None => false
+		};
+		if bucket_now_empty {
+			self.value_index.remove(&value);
+		}
+	}
+
+	/* records the pre-image of key and of the val_quant bucket for value, the
+	   first time either is touched within frame. A later SET/UNSET against
+	   the same key or bucket within the same frame is already reversible via
+	   this first recording, so it's left alone. */
+	fn record_undo(&self, frame: &mut savepoint, key: &str, value: i32) {
+		if !frame.key_undo.contains_key(key) {
+			frame.key_undo.insert(String::from(key), self.key_val.get(key).cloned());
+		}
+		if !frame.val_undo.contains_key(&value) {
+			frame.val_undo.insert(value, self.val_quant.get(&value).cloned());
+		}
+	}
+
 	/* performs actions required for the SET command */
-	fn set(&mut self, key:String, val:i32) {
+	fn set(&mut self, key:String, val:i32, frame: Option<&mut savepoint>) -> Result<(), DbError> {
+		if let Some(frame) = frame {
+			if let Some(&current_val) = self.key_val.get(key.as_str()) {
+				self.record_undo(frame, key.as_str(), current_val);
+			}
+			self.record_undo(frame, key.as_str(), val);
+		}
+
 		if self.key_val.contains_key(key.as_str()) {
 			if let Some(current_val) = self.key_val.get(key.as_str()) {
 				/* decrement val_quant count for old value attached to key if key  exists */
@@ -74,10 +216,11 @@ impl transaction {
 						*count -= 1;
 					}
 				}
+				self.unindex(key.as_str(), *current_val);
 			}
 		}
 
-		self.key_val.insert(key, val);
+		self.key_val.insert(key.clone(), val);
 		/* increment the corresponding value within val_quant */
 		if self.val_quant.contains_key(&val) {
 			if let Some(count) = self.val_quant.get_mut(&val) {
@@ -88,43 +231,140 @@ impl transaction {
 			/* first insertion of value starts off the count in val_quant */
 			self.val_quant.insert(val, 1);
 		}
+		self.value_index.entry(val).or_insert_with(HashSet::new).insert(key);
+
+		Ok(())
 	}
 
-	fn unset(&mut self, key:String) {
-		/* if the given key doesn't exist then nothing needs to be done. */
-		if self.key_val.contains_key(key.as_str()) {
-			/* remove key from key_val table */
-			let value_option = self.key_val.remove(key.as_str());
+	fn unset(&mut self, key:String, frame: Option<&mut savepoint>) -> Result<(), DbError> {
+		/* if the given key doesn't exist then there is nothing to remove */
+		if !self.key_val.contains_key(key.as_str()) {
+			return Err(DbError::KeyNotFound(key));
+		}
 
-			if let Some(value) = value_option {
-				/* decrement count within val_quant */
-				if let Some(count) = self.val_quant.get_mut(&value) {
-					*count -= 1;
-				}
+		if let Some(frame) = frame {
+			if let Some(&current_val) = self.key_val.get(key.as_str()) {
+				self.record_undo(frame, key.as_str(), current_val);
 			}
 		}
+
+		/* remove key from key_val table */
+		let value_option = self.key_val.remove(key.as_str());
+
+		if let Some(value) = value_option {
+			/* decrement count within val_quant */
+			if let Some(count) = self.val_quant.get_mut(&value) {
+				*count -= 1;
+			}
+			self.unindex(key.as_str(), value);
+		}
+
+		Ok(())
 	}
 
 	/* retrieves the value stored at a given key */
-	fn get(&self, key:String) {
-		if let Some(value) = self.key_val.get(key.as_str()) {
-			println!("> {}", value);
+	fn get(&self, key:String) -> Option<i32> {
+		self.key_val.get(key.as_str()).cloned()
+	}
+
+	fn num_equal_to(&self, key:i32) -> i32 {
+		*self.val_quant.get(&key).unwrap_or(&0)
+	}
+
+	/* keys whose value falls within [lo, hi], sorted by value then key */
+	fn range(&self, lo:i32, hi:i32) -> Vec<String> {
+		let mut keys = Vec::new();
+
+		/* an empty bound (lo > hi) matches nothing; BTreeMap::range panics
+		   if asked to iterate it, so bail out before calling it */
+		if lo > hi {
+			return keys;
 		}
-		else {
-			println!("> NULL");
+
+		for (_, bucket) in self.value_index.range(lo..=hi) {
+			let mut bucket_keys = bucket.iter().cloned().collect::<Vec<String>>();
+			bucket_keys.sort();
+			keys.extend(bucket_keys);
 		}
+		keys
 	}
 
-	fn num_equal_to(&self, key:i32) {
-		if self.val_quant.contains_key(&key) {
-			if let Some(count) = self.val_quant.get(&key) {
-				println!("> {}", count)
+	/* all keys currently set to the given value */
+	fn keys_with_value(&self, val:i32) -> Vec<String> {
+		match self.value_index.get(&val) {
+			Some(bucket) => {
+				let mut keys = bucket.iter().cloned().collect::<Vec<String>>();
+				keys.sort();
+				keys
+			},
+			None => Vec::new()
+		}
+	}
+}
+
+/* pluggable durability backend: loads prior state on startup and persists committed state */
+trait Storage {
+	fn load(&self) -> HashMap<String, i32>;
+	fn persist(&mut self, kv: &HashMap<String, i32>);
+}
+
+/* append-only, file-backed Storage implementation */
+struct FileStorage {
+	path: String,
+	last_known: HashMap<String, i32>    // last state persisted, used to log only what changed
+}
+
+impl FileStorage {
+	fn new(path: &str) -> FileStorage {
+		FileStorage {
+			path: String::from(path),
+			last_known: HashMap::new()
+		}
+	}
+}
+
+impl Storage for FileStorage {
+	/* replays the on-disk log to reconstruct the key/value state */
+	fn load(&self) -> HashMap<String, i32> {
+		let mut kv = HashMap::new();
+
+		if let Ok(file) = std::fs::File::open(&self.path) {
+			for line in io::BufReader::new(file).lines() {
+				if let Ok(entry) = line {
+					let parts = entry.split_whitespace().collect::<Vec<&str>>();
+					if parts.len() == 3 && parts[0] == "SET" {
+						if let Ok(value) = parts[2].parse::<i32>() {
+							kv.insert(String::from(parts[1]), value);
+						}
+					}
+					else if parts.len() == 2 && parts[0] == "UNSET" {
+						kv.remove(parts[1]);
+					}
+				}
 			}
 		}
-		else {
-			/* key does not exist within the current transaction */
-			println!("> 0");
+
+		kv
+	}
+
+	/* appends the SET/UNSET lines needed to bring the log up to date with kv */
+	fn persist(&mut self, kv: &HashMap<String, i32>) {
+		let file = OpenOptions::new().create(true).append(true).open(&self.path);
+
+		if let Ok(mut file) = file {
+			for (key, value) in kv.iter() {
+				if self.last_known.get(key) != Some(value) {
+					let _ = writeln!(file, "SET {} {}", key, value);
+				}
+			}
+			for key in self.last_known.keys() {
+				if !kv.contains_key(key) {
+					let _ = writeln!(file, "UNSET {}", key);
+				}
+			}
 		}
+
+		self.last_known = kv.clone();
 	}
 }
 
@@ -167,6 +407,33 @@ fn is_valid_command(command: &Vec<&str>) -> Result<db_command, &'static str> {
 			else {
 				Err("> Incorrect number of arguments for NUMEQUALTO command")
 			},
+		"RANGE" =>
+			if command.len() == 3 {
+				let lo_container = command[1].parse::<i32>();
+				let hi_container = command[2].parse::<i32>();
+				if let (Ok(lo), Ok(hi)) = (lo_container, hi_container) {
+					Ok(RANGE(lo, hi))
+				}
+				else {
+					Err("> Invalid bound supplied to RANGE")
+				}
+			}
+			else {
+				Err("> Incorrect number of arguments for RANGE command")
+			},
+		"KEYSWITHVALUE" =>
+			if command.len() == 2 {
+				let value_container = command[1].parse::<i32>();
+				if let Ok(value) = value_container {
+					Ok(KEYSWITHVALUE(value))
+				}
+				else {
+					Err("> Invalid value supplied to KEYSWITHVALUE")
+				}
+			}
+			else {
+				Err("> Incorrect number of arguments for KEYSWITHVALUE command")
+			},
 		"UNSET" =>
 			if command.len() == 2 {
 				let key = String::from(command[1]);
@@ -186,6 +453,10 @@ fn is_valid_command(command: &Vec<&str>) -> Result<db_command, &'static str> {
 			if command.len() == 1 {
 				Ok(ROLLBACK)
 			}
+			else if command.len() == 3 && command[1] == "TO" {
+				let name = String::from(command[2]);
+				Ok(ROLLBACKTO(name))
+			}
 			else {
 				Err("> Incorrect number of arguments for ROLLBACK command")
 			},
@@ -196,6 +467,22 @@ fn is_valid_command(command: &Vec<&str>) -> Result<db_command, &'static str> {
 			else {
 				Err("> Incorrect number of arguments for BEGIN command")
 			},
+		"SAVEPOINT" =>
+			if command.len() == 2 {
+				let name = String::from(command[1]);
+				Ok(SAVEPOINT(name))
+			}
+			else {
+				Err("> Incorrect number of arguments for SAVEPOINT command")
+			},
+		"RELEASE" =>
+			if command.len() == 2 {
+				let name = String::from(command[1]);
+				Ok(RELEASE(name))
+			}
+			else {
+				Err("> Incorrect number of arguments for RELEASE command")
+			},
 
 		"END" =>
 		if command.len() == 1 {
@@ -210,36 +497,115 @@ fn is_valid_command(command: &Vec<&str>) -> Result<db_command, &'static str> {
 }
 
 /* function that accepts a validated command and runs the command on the provided transaction */
-fn dispatch_command(cmd:db_command, ct:&mut transaction, ts:&mut Vec<transaction>) {
+fn dispatch_command(cmd:db_command, ct:&mut transaction, ts:&mut Vec<savepoint>, storage:&mut dyn Storage) {
     match cmd {
-		SET(key, value) => ct.set(key, value),
-		GET(key) => ct.get(key),
-		NUMEQUALTO(value) => ct.num_equal_to(value),
-		UNSET(key) => ct.unset(key),
+		SET(key, value) => {
+			let _ = ct.set(key, value, ts.last_mut());
+			/* no open transaction means this change is committed, so persist it now */
+			if ts.len() == 0 {
+				storage.persist(&ct.key_val);
+			}
+		},
+		GET(key) => {
+			match ct.get(key) {
+				Some(value) => println!("> {}", value),
+				None => println!("> NULL")
+			}
+		},
+		NUMEQUALTO(value) => println!("> {}", ct.num_equal_to(value)),
+		RANGE(lo, hi) => {
+			let keys = ct.range(lo, hi);
+			if keys.is_empty() {
+				println!("> NONE");
+			}
+			else {
+				println!("> {}", keys.join(" "));
+			}
+		},
+		KEYSWITHVALUE(value) => {
+			let keys = ct.keys_with_value(value);
+			if keys.is_empty() {
+				println!("> NONE");
+			}
+			else {
+				println!("> {}", keys.join(" "));
+			}
+		},
+		UNSET(key) => {
+			/* a missing key is a silent no-op, same as before this returned a Result */
+			let _ = ct.unset(key, ts.last_mut());
+			if ts.len() == 0 {
+				storage.persist(&ct.key_val);
+			}
+		},
 		BEGIN => {
-			/* Add current transaction to transaction stack */
-			ts.push(transaction{
-				val_quant: ct.val_quant.clone(),
-				key_val: ct.key_val.clone()
-			});
+			/* open an anonymous savepoint; starts empty, no database-wide clone */
+			ts.push(savepoint::new(None));
+		},
+		SAVEPOINT(name) => {
+			/* tags a stack entry so ROLLBACK TO / RELEASE can target it by name */
+			ts.push(savepoint::new(Some(name)));
 		},
 		ROLLBACK => {
 			if ts.len() == 0 {
 				println!("> NO TRANSACTION");
 			}
 			else {
-				if let Some(tran) = ts.pop() {
-					ct.val_quant = tran.val_quant;
-					ct.key_val = tran.key_val;
+				if let Some(sp) = ts.pop() {
+					apply_undo(ct, &sp);
 				}
 			}
 		},
+		ROLLBACKTO(name) => {
+			match find_savepoint(ts, &name) {
+				Some(idx) => {
+					/* undo frames from the top down to the named savepoint, newest first */
+					let mut i = ts.len();
+					while i > idx {
+						i -= 1;
+						apply_undo(ct, &ts[i]);
+					}
+					ts.truncate(idx + 1);
+					/* the savepoint itself stays open, ready to be targeted again */
+					ts[idx].key_undo.clear();
+					ts[idx].val_undo.clear();
+				},
+				None => println!("> {}", DbError::SavepointNotFound(name))
+			}
+		},
+		RELEASE(name) => {
+			match find_savepoint(ts, &name) {
+				Some(idx) => {
+					/* fold the named savepoint and anything nested inside it into its parent */
+					while ts.len() > idx {
+						if let Some(sp) = ts.pop() {
+							if let Some(parent) = ts.last_mut() {
+								merge_undo(parent, sp);
+							}
+						}
+					}
+					if ts.len() == 0 {
+						storage.persist(&ct.key_val);
+					}
+				},
+				None => println!("> {}", DbError::SavepointNotFound(name))
+			}
+		},
 		COMMIT => {
 			if ts.len() == 0 {
 				println!("> NO TRANSACTION");
 			}
 			else {
-				ts.clear();
+				/* merge the innermost transaction into its parent, rather than
+				   discarding the whole stack, so nested BEGINs stay open */
+				if let Some(sp) = ts.pop() {
+					if let Some(parent) = ts.last_mut() {
+						merge_undo(parent, sp);
+					}
+				}
+				if ts.len() == 0 {
+					storage.persist(&ct.key_val);
+				}
 			}
 		},
 		END => process::exit(0)
@@ -247,23 +613,117 @@ fn dispatch_command(cmd:db_command, ct:&mut transaction, ts:&mut Vec<transaction
 }
 
 fn main() {
-	let mut transaction_stack:Vec<transaction> = Vec::new();
+	let mut storage = FileStorage::new(DB_LOG_PATH);
+	let mut transaction_stack:Vec<savepoint> = Vec::new();
 	let mut current_transaction = transaction::new();
+
+	/* recover durable state from the on-disk log before accepting new commands */
+	let recovered = storage.load();
+	/* seed last_known with what's already durable, so the first persist()
+	   only logs what actually changes instead of re-appending every
+	   recovered key (and silently dropping any UNSET of one of them) */
+	storage.last_known = recovered.clone();
+	for (key, value) in recovered {
+		let _ = current_transaction.set(key, value, None);
+	}
+
     /* read in database requests */
 	let stdin = io::stdin();
 	 for line in stdin.lock().lines() {
 		let input = String::from(line.expect("Read Error"));
-	    let command_and_args = input.split_whitespace()
-		  							.collect::<Vec<&str>>();
-
-		/* Generate db_command if valid and dispatch if no error found */
-		let cmd = is_valid_command(&command_and_args);
-		match cmd {
-			Ok(db_cmd) => {
-				println!("{}", input);	// this reprints the original command. Needed for HackerRank test cases.
-				dispatch_command(db_cmd, &mut current_transaction, &mut transaction_stack);
-			},
-			Err(msg) => println!("{}", msg)
+
+		/* a line may batch several statements separated by ';'; run each in
+		   order and keep going if one of them is invalid */
+		for statement in input.split(';') {
+			let statement = statement.trim();
+			if statement.is_empty() {
+				continue;
+			}
+
+			let command_and_args = statement.split_whitespace()
+											.collect::<Vec<&str>>();
+
+			/* Generate db_command if valid and dispatch if no error found */
+			let cmd = is_valid_command(&command_and_args);
+			match cmd {
+				Ok(db_cmd) => {
+					println!("{}", statement);	// this reprints the original command. Needed for HackerRank test cases.
+					dispatch_command(db_cmd, &mut current_transaction, &mut transaction_stack, &mut storage);
+				},
+				Err(msg) => println!("{}", msg)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn set_then_get_returns_the_value() {
+		let mut t = transaction::new();
+		assert!(t.set(String::from("a"), 1, None).is_ok());
+		assert_eq!(t.get(String::from("a")), Some(1));
+	}
+
+	#[test]
+	fn get_on_missing_key_returns_none() {
+		let t = transaction::new();
+		assert_eq!(t.get(String::from("a")), None);
+	}
+
+	#[test]
+	fn unset_on_missing_key_returns_key_not_found() {
+		let mut t = transaction::new();
+		match t.unset(String::from("a"), None) {
+			Err(DbError::KeyNotFound(ref key)) => assert_eq!(key, "a"),
+			other => panic!("expected KeyNotFound, got {:?}", other)
 		}
 	}
+
+	#[test]
+	fn unset_existing_key_removes_it() {
+		let mut t = transaction::new();
+		t.set(String::from("a"), 1, None).unwrap();
+		assert!(t.unset(String::from("a"), None).is_ok());
+		assert_eq!(t.get(String::from("a")), None);
+	}
+
+	#[test]
+	fn num_equal_to_counts_keys_sharing_a_value() {
+		let mut t = transaction::new();
+		t.set(String::from("a"), 1, None).unwrap();
+		t.set(String::from("b"), 1, None).unwrap();
+		t.set(String::from("c"), 2, None).unwrap();
+		assert_eq!(t.num_equal_to(1), 2);
+		assert_eq!(t.num_equal_to(2), 1);
+		assert_eq!(t.num_equal_to(99), 0);
+	}
+
+	#[test]
+	fn range_returns_keys_within_bounds_sorted_by_value_then_key() {
+		let mut t = transaction::new();
+		t.set(String::from("b"), 20, None).unwrap();
+		t.set(String::from("a"), 10, None).unwrap();
+		t.set(String::from("c"), 10, None).unwrap();
+		t.set(String::from("d"), 30, None).unwrap();
+		assert_eq!(t.range(5, 20), vec![String::from("a"), String::from("c"), String::from("b")]);
+	}
+
+	#[test]
+	fn range_with_lo_greater_than_hi_is_empty_not_a_panic() {
+		let mut t = transaction::new();
+		t.set(String::from("a"), 10, None).unwrap();
+		assert_eq!(t.range(10, 5), Vec::<String>::new());
+	}
+
+	#[test]
+	fn keys_with_value_returns_the_matching_key_set() {
+		let mut t = transaction::new();
+		t.set(String::from("a"), 1, None).unwrap();
+		t.set(String::from("b"), 1, None).unwrap();
+		assert_eq!(t.keys_with_value(1), vec![String::from("a"), String::from("b")]);
+		assert_eq!(t.keys_with_value(2), Vec::<String>::new());
+	}
 }